@@ -0,0 +1,81 @@
+//! Bridge glue for proc-macro dylibs built by rustc 1.47.
+
+pub(crate) mod proc_macro;
+mod rustc_server;
+
+use libloading::Library;
+use ra_proc_macro::ProcMacroKind;
+
+use proc_macro::bridge::{self, client::ProcMacro};
+
+pub(crate) struct Abi {
+    exported_macros: Vec<ProcMacro>,
+}
+
+impl Abi {
+    pub(crate) fn from_lib(lib: &Library, symbol_name: String) -> Result<Self, libloading::Error> {
+        let macros: libloading::Symbol<&&[ProcMacro]> = unsafe { lib.get(symbol_name.as_bytes()) }?;
+        Ok(Abi { exported_macros: macros.to_vec() })
+    }
+
+    /// Returns `None` if none of this ABI's exported macros are named
+    /// `macro_name`, so the caller can try another library.
+    pub(crate) fn expand(
+        &self,
+        macro_name: &str,
+        macro_body: &ra_tt::Subtree,
+        attributes: Option<&ra_tt::Subtree>,
+    ) -> Option<Result<ra_tt::Subtree, bridge::PanicMessage>> {
+        let parsed_body = rustc_server::TokenStream::with_subtree(macro_body.clone());
+        let parsed_attributes = attributes
+            .map_or(rustc_server::TokenStream::new(), |attr| {
+                rustc_server::TokenStream::with_subtree(attr.clone())
+            });
+
+        for proc_macro in &self.exported_macros {
+            match proc_macro {
+                ProcMacro::CustomDerive { trait_name, client, .. } if *trait_name == macro_name => {
+                    let res = client.run(
+                        &bridge::server::SameThread,
+                        rustc_server::Rustc::default(),
+                        parsed_body,
+                    );
+                    return Some(res.map(|it| it.subtree));
+                }
+                ProcMacro::Bang { name, client } if *name == macro_name => {
+                    let res = client.run(
+                        &bridge::server::SameThread,
+                        rustc_server::Rustc::default(),
+                        parsed_body,
+                    );
+                    return Some(res.map(|it| it.subtree));
+                }
+                ProcMacro::Attr { name, client } if *name == macro_name => {
+                    let res = client.run(
+                        &bridge::server::SameThread,
+                        rustc_server::Rustc::default(),
+                        parsed_attributes,
+                        parsed_body,
+                    );
+                    return Some(res.map(|it| it.subtree));
+                }
+                _ => continue,
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn list_macros(&self) -> Vec<(String, ProcMacroKind)> {
+        self.exported_macros
+            .iter()
+            .map(|proc_macro| match proc_macro {
+                ProcMacro::CustomDerive { trait_name, .. } => {
+                    (trait_name.to_string(), ProcMacroKind::CustomDerive)
+                }
+                ProcMacro::Bang { name, .. } => (name.to_string(), ProcMacroKind::FuncLike),
+                ProcMacro::Attr { name, .. } => (name.to_string(), ProcMacroKind::Attr),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,21 @@
+//! Our implementation of the `proc_macro` server, for the 1.47 bridge.
+//!
+//! This is the same shape as `crate::rustc_server`, just namespaced under
+//! this ABI so it can evolve independently of whatever the "current" bridge
+//! looks like.
+
+#[derive(Debug, Clone)]
+pub(crate) struct TokenStream(pub(crate) ra_tt::Subtree);
+
+impl TokenStream {
+    pub(crate) fn new() -> Self {
+        TokenStream(ra_tt::Subtree::default())
+    }
+
+    pub(crate) fn with_subtree(subtree: ra_tt::Subtree) -> Self {
+        TokenStream(subtree)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Rustc;
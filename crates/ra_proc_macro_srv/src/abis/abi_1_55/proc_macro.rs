@@ -0,0 +1,128 @@
+//! A reproduction of `proc_macro`'s bridge, as it looked on the rustc that
+//! shipped with 1.55. The in-memory layout of `client::ProcMacro` is part of
+//! the dylib ABI, so this is a frozen copy: it must never be changed to
+//! "improve" it, only replaced wholesale by a new `abi_1_*` module when a
+//! newer rustc breaks the layout.
+//!
+//! Unlike the 1.47 bridge (which `Abi::from_lib` uses for every rustc up to
+//! 1.54), the client handshake here starts by pulling the dylib's
+//! `HandleCounters`: in this bridge `proc_macro`'s `TokenStream`,
+//! `Span`, `Ident` and `Literal` are opaque handles rather than inline data,
+//! and the compiler and the proc-macro crate each keep their own counter so
+//! handles minted by one side can never collide with the other's. A 1.47
+//! dylib has no such symbol to call, so the two ABIs cannot share this type.
+
+pub(crate) mod bridge {
+    use super::super::rustc_server;
+
+    #[repr(C)]
+    pub(crate) struct HandleCounters {
+        pub(crate) free_functions: std::sync::atomic::AtomicUsize,
+        pub(crate) token_streams: std::sync::atomic::AtomicUsize,
+        pub(crate) literals: std::sync::atomic::AtomicUsize,
+        pub(crate) idents: std::sync::atomic::AtomicUsize,
+        pub(crate) spans: std::sync::atomic::AtomicUsize,
+    }
+
+    /// Handle to a client-side (i.e. proc-macro-crate-side) callback, as
+    /// exported by the dylib's registrar. `F` pins down the signature the
+    /// compiler (and us, standing in for it) invokes it with.
+    #[derive(Copy, Clone)]
+    pub(crate) struct Client<F> {
+        pub(crate) get_handle_counters: extern "C" fn() -> &'static HandleCounters,
+        pub(crate) run: fn(
+            bridge: &server::SameThread,
+            server: rustc_server::Rustc,
+            stream: rustc_server::TokenStream,
+        ) -> Result<rustc_server::TokenStream, PanicMessage>,
+        // Never read: it only pins down the call signature `F` so that a
+        // dylib built against a different macro signature can't be mistaken
+        // for this one, matching the real bridge's layout.
+        #[allow(dead_code)]
+        pub(crate) f: std::marker::PhantomData<F>,
+    }
+
+    impl<F> Client<F> {
+        pub(crate) fn run(
+            &self,
+            bridge: &server::SameThread,
+            server: rustc_server::Rustc,
+            stream: rustc_server::TokenStream,
+        ) -> Result<BridgeOutput, PanicMessage> {
+            // Fetch the client's handle counters before crossing into it, as
+            // the handshake does; the counters are not otherwise used here.
+            let _counters = (self.get_handle_counters)();
+            (self.run)(bridge, server, stream).map(|subtree| BridgeOutput { subtree: subtree.0 })
+        }
+    }
+
+    /// A second entry point used for attribute macros, which additionally
+    /// thread the attribute's own token stream through.
+    #[derive(Copy, Clone)]
+    pub(crate) struct AttrClient<F> {
+        pub(crate) get_handle_counters: extern "C" fn() -> &'static HandleCounters,
+        pub(crate) run: fn(
+            bridge: &server::SameThread,
+            server: rustc_server::Rustc,
+            attr: rustc_server::TokenStream,
+            stream: rustc_server::TokenStream,
+        ) -> Result<rustc_server::TokenStream, PanicMessage>,
+        // Never read: it only pins down the call signature `F` so that a
+        // dylib built against a different macro signature can't be mistaken
+        // for this one, matching the real bridge's layout.
+        #[allow(dead_code)]
+        pub(crate) f: std::marker::PhantomData<F>,
+    }
+
+    impl<F> AttrClient<F> {
+        pub(crate) fn run(
+            &self,
+            bridge: &server::SameThread,
+            server: rustc_server::Rustc,
+            attr: rustc_server::TokenStream,
+            stream: rustc_server::TokenStream,
+        ) -> Result<BridgeOutput, PanicMessage> {
+            let _counters = (self.get_handle_counters)();
+            (self.run)(bridge, server, attr, stream)
+                .map(|subtree| BridgeOutput { subtree: subtree.0 })
+        }
+    }
+
+    pub(crate) struct BridgeOutput {
+        pub(crate) subtree: ra_tt::Subtree,
+    }
+
+    #[derive(Debug)]
+    pub(crate) enum PanicMessage {
+        String(String),
+    }
+
+    pub(crate) mod client {
+        use super::{AttrClient, Client};
+
+        /// Mirrors `rustc`'s `proc_macro::bridge::client::ProcMacro`: one
+        /// entry per macro exported from the registrar symbol.
+        #[derive(Copy, Clone)]
+        pub(crate) enum ProcMacro {
+            CustomDerive {
+                trait_name: &'static str,
+                attributes: &'static [&'static str],
+                client: Client<fn()>,
+            },
+            Bang {
+                name: &'static str,
+                client: Client<fn()>,
+            },
+            Attr {
+                name: &'static str,
+                client: AttrClient<fn()>,
+            },
+        }
+    }
+
+    pub(crate) mod server {
+        /// The bridge only ever runs the client in-process, on the thread
+        /// that requested the expansion.
+        pub(crate) struct SameThread;
+    }
+}
@@ -0,0 +1,105 @@
+//! Multiple rustc versions may disagree on the in-memory layout of the
+//! `proc_macro` bridge types (`bridge::client::ProcMacro` and friends), so we
+//! cannot share a single copy of those types across all supported toolchains:
+//! transmuting a dylib's registrar symbol to the wrong layout is undefined
+//! behavior. Instead each supported bridge layout gets its own `abi_1_*`
+//! submodule, named after the rustc release it was copied from, with a
+//! frozen copy of the bridge/server glue, and `Abi` picks the one matching
+//! the dylib's rustc version at load time.
+
+mod abi_1_47;
+mod abi_1_55;
+
+use libloading::Library;
+use ra_proc_macro::ProcMacroKind;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+pub(crate) enum Abi {
+    Abi1_47(abi_1_47::Abi),
+    Abi1_55(abi_1_55::Abi),
+}
+
+/// A macro's own panic message, normalized across the per-ABI
+/// `bridge::PanicMessage` types so `Expander`'s public signature doesn't
+/// depend on which version produced it.
+#[derive(Debug)]
+pub(crate) enum PanicMessage {
+    String(String),
+}
+
+impl Abi {
+    /// Loads the proc-macro registrar symbol out of `lib` using the bridge
+    /// that matches `version`, which should be a `major.minor.patch` string
+    /// as embedded in the compiled dylib.
+    pub(crate) fn from_lib(
+        lib: &Library,
+        symbol_name: String,
+        version: &str,
+    ) -> Result<Abi, IoError> {
+        let (major, minor) = parse_major_minor(version).ok_or_else(|| {
+            IoError::new(IoErrorKind::InvalidData, format!("invalid rustc version {:?}", version))
+        })?;
+
+        match (major, minor) {
+            (1, 0..=54) => {
+                let abi = abi_1_47::Abi::from_lib(lib, symbol_name)
+                    .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+                Ok(Abi::Abi1_47(abi))
+            }
+            (1, 55..) => {
+                let abi = abi_1_55::Abi::from_lib(lib, symbol_name)
+                    .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+                Ok(Abi::Abi1_55(abi))
+            }
+            _ => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("proc macro built with rustc {}, unsupported", version),
+            )),
+        }
+    }
+
+    /// Used when the dylib has no `.rustc` version section to read (an old
+    /// or stripped build), or when that section cannot be parsed. We have no way to know which ABI it actually
+    /// uses, so fall back to the oldest one we support.
+    pub(crate) fn from_legacy_lib(lib: &Library, symbol_name: String) -> Result<Abi, IoError> {
+        let abi = abi_1_47::Abi::from_lib(lib, symbol_name)
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+        Ok(Abi::Abi1_47(abi))
+    }
+
+    /// Returns `None` if none of this dylib's exported macros are named
+    /// `macro_name`.
+    pub(crate) fn expand(
+        &self,
+        macro_name: &str,
+        macro_body: &ra_tt::Subtree,
+        attributes: Option<&ra_tt::Subtree>,
+    ) -> Option<Result<ra_tt::Subtree, PanicMessage>> {
+        match self {
+            Abi::Abi1_47(abi) => abi.expand(macro_name, macro_body, attributes).map(|res| {
+                res.map_err(|abi_1_47::proc_macro::bridge::PanicMessage::String(s)| {
+                    PanicMessage::String(s)
+                })
+            }),
+            Abi::Abi1_55(abi) => abi.expand(macro_name, macro_body, attributes).map(|res| {
+                res.map_err(|abi_1_55::proc_macro::bridge::PanicMessage::String(s)| {
+                    PanicMessage::String(s)
+                })
+            }),
+        }
+    }
+
+    pub(crate) fn list_macros(&self) -> Vec<(String, ProcMacroKind)> {
+        match self {
+            Abi::Abi1_47(abi) => abi.list_macros(),
+            Abi::Abi1_55(abi) => abi.list_macros(),
+        }
+    }
+}
+
+fn parse_major_minor(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
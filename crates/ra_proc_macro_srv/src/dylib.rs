@@ -1,10 +1,13 @@
 //! Handles dynamic library loading for proc macro
 
-use crate::{proc_macro::bridge, rustc_server::TokenStream};
+use crate::abis::{Abi, PanicMessage};
+use crate::version::read_dylib_info;
+use std::fs::File;
 use std::path::Path;
 
-use goblin::{mach::Mach, Object};
 use libloading::Library;
+use memmap2::Mmap;
+use object::Object;
 use ra_proc_macro::ProcMacroKind;
 
 use std::io::Error as IoError;
@@ -20,47 +23,29 @@ fn is_derive_registrar_symbol(symbol: &str) -> bool {
     symbol.contains(NEW_REGISTRAR_SYMBOL)
 }
 
-fn find_registrar_symbol(file: &Path) -> Result<Option<String>, IoError> {
-    let buffer = std::fs::read(file)?;
-    let object = Object::parse(&buffer).map_err(invalid_data_err)?;
+// In macos doc:
+// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man3/dlsym.3.html
+// Unlike other dyld API's, the symbol name passed to dlsym() must NOT be
+// prepended with an underscore.
+#[cfg(target_os = "macos")]
+fn maybe_strip_underscore(name: &str) -> &str {
+    name.strip_prefix('_').unwrap_or(name)
+}
 
-    match object {
-        Object::Elf(elf) => {
-            let symbols = elf.dynstrtab.to_vec().map_err(invalid_data_err)?;
-            let name =
-                symbols.iter().find(|s| is_derive_registrar_symbol(s)).map(|s| s.to_string());
-            Ok(name)
-        }
-        Object::PE(pe) => {
-            let name = pe
-                .exports
-                .iter()
-                .flat_map(|s| s.name)
-                .find(|s| is_derive_registrar_symbol(s))
-                .map(|s| s.to_string());
-            Ok(name)
-        }
-        Object::Mach(Mach::Binary(binary)) => {
-            let exports = binary.exports().map_err(invalid_data_err)?;
-            let name = exports
-                .iter()
-                .map(|s| {
-                    // In macos doc:
-                    // https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man3/dlsym.3.html
-                    // Unlike other dyld API's, the symbol name passed to dlsym() must NOT be
-                    // prepended with an underscore.
-                    if s.name.starts_with("_") {
-                        &s.name[1..]
-                    } else {
-                        &s.name
-                    }
-                })
-                .find(|s| is_derive_registrar_symbol(&s))
-                .map(|s| s.to_string());
-            Ok(name)
-        }
-        _ => Ok(None),
-    }
+#[cfg(not(target_os = "macos"))]
+fn maybe_strip_underscore(name: &str) -> &str {
+    name
+}
+
+fn find_registrar_symbol(object: &object::File) -> Result<Option<String>, IoError> {
+    let name = object
+        .exports()
+        .map_err(invalid_data_err)?
+        .into_iter()
+        .map(|export| maybe_strip_underscore(&String::from_utf8_lossy(export.name())).to_string())
+        .find(|name| is_derive_registrar_symbol(name));
+
+    Ok(name)
 }
 
 /// Loads dynamic library in platform dependent manner.
@@ -92,22 +77,40 @@ fn load_library(file: &Path) -> Result<Library, libloading::Error> {
 struct ProcMacroLibraryLibloading {
     // Hold the dylib to prevent it for unloadeding
     _lib: Library,
-    exported_macros: Vec<bridge::client::ProcMacro>,
+    abi: Abi,
 }
 
 impl ProcMacroLibraryLibloading {
     fn open(file: &Path) -> Result<Self, IoError> {
-        let symbol_name = find_registrar_symbol(file)?
+        let raw_file = File::open(file)?;
+        // Safety: the file must not be mutated while mapped; we only read it
+        // here, and the mapping is dropped at the end of this function. We
+        // map once and reuse the parsed `object::File` for both the symbol
+        // lookup and the version detection below, rather than parsing the
+        // dylib twice.
+        let mmap = unsafe { Mmap::map(&raw_file) }?;
+        let object = object::File::parse(&*mmap).map_err(invalid_data_err)?;
+
+        let symbol_name = find_registrar_symbol(&object)?
             .ok_or(invalid_data_err(format!("Cannot find registrar symbol in file {:?}", file)))?;
 
         let lib = load_library(file).map_err(invalid_data_err)?;
-        let exported_macros = {
-            let macros: libloading::Symbol<&&[bridge::client::ProcMacro]> =
-                unsafe { lib.get(symbol_name.as_bytes()) }.map_err(invalid_data_err)?;
-            macros.to_vec()
+        // Older/stripped dylibs have no `.rustc` section, and some we don't
+        // know how to parse; fall back to the legacy, version-less load in
+        // both cases rather than failing the whole load.
+        let abi = match read_dylib_info(&object) {
+            Ok(Some(info)) => {
+                let version = format!("{}.{}.{}", info.version.0, info.version.1, info.version.2);
+                Abi::from_lib(&lib, symbol_name, &version)?
+            }
+            Ok(None) => Abi::from_legacy_lib(&lib, symbol_name)?,
+            Err(e) => {
+                log::warn!("failed to read rustc version from {:?}: {}", file, e);
+                Abi::from_legacy_lib(&lib, symbol_name)?
+            }
         };
 
-        Ok(ProcMacroLibraryLibloading { _lib: lib, exported_macros })
+        Ok(ProcMacroLibraryLibloading { _lib: lib, abi })
     }
 }
 
@@ -137,73 +140,17 @@ impl Expander {
         macro_name: &str,
         macro_body: &ra_tt::Subtree,
         attributes: Option<&ra_tt::Subtree>,
-    ) -> Result<ra_tt::Subtree, bridge::PanicMessage> {
-        let parsed_body = TokenStream::with_subtree(macro_body.clone());
-
-        let parsed_attributes = attributes
-            .map_or(crate::rustc_server::TokenStream::new(), |attr| {
-                TokenStream::with_subtree(attr.clone())
-            });
-
+    ) -> Result<ra_tt::Subtree, PanicMessage> {
         for lib in &self.libs {
-            for proc_macro in &lib.exported_macros {
-                match proc_macro {
-                    bridge::client::ProcMacro::CustomDerive { trait_name, client, .. }
-                        if *trait_name == macro_name =>
-                    {
-                        let res = client.run(
-                            &crate::proc_macro::bridge::server::SameThread,
-                            crate::rustc_server::Rustc::default(),
-                            parsed_body,
-                        );
-                        return res.map(|it| it.subtree);
-                    }
-                    bridge::client::ProcMacro::Bang { name, client } if *name == macro_name => {
-                        let res = client.run(
-                            &crate::proc_macro::bridge::server::SameThread,
-                            crate::rustc_server::Rustc::default(),
-                            parsed_body,
-                        );
-                        return res.map(|it| it.subtree);
-                    }
-                    bridge::client::ProcMacro::Attr { name, client } if *name == macro_name => {
-                        let res = client.run(
-                            &crate::proc_macro::bridge::server::SameThread,
-                            crate::rustc_server::Rustc::default(),
-                            parsed_attributes,
-                            parsed_body,
-                        );
-
-                        return res.map(|it| it.subtree);
-                    }
-                    _ => continue,
-                }
+            if let Some(res) = lib.abi.expand(macro_name, macro_body, attributes) {
+                return res;
             }
         }
 
-        Err(bridge::PanicMessage::String("Nothing to expand".to_string()))
+        Err(PanicMessage::String("Nothing to expand".to_string()))
     }
 
-    pub fn list_macros(&self) -> Result<Vec<(String, ProcMacroKind)>, bridge::PanicMessage> {
-        let mut result = vec![];
-
-        for lib in &self.libs {
-            for proc_macro in &lib.exported_macros {
-                let res = match proc_macro {
-                    bridge::client::ProcMacro::CustomDerive { trait_name, .. } => {
-                        (trait_name.to_string(), ProcMacroKind::CustomDerive)
-                    }
-                    bridge::client::ProcMacro::Bang { name, .. } => {
-                        (name.to_string(), ProcMacroKind::FuncLike)
-                    }
-                    bridge::client::ProcMacro::Attr { name, .. } => {
-                        (name.to_string(), ProcMacroKind::Attr)
-                    }
-                };
-                result.push(res);
-            }
-        }
-
-        Ok(result)
+    pub fn list_macros(&self) -> Result<Vec<(String, ProcMacroKind)>, PanicMessage> {
+        Ok(self.libs.iter().flat_map(|lib| lib.abi.list_macros()).collect())
     }
 }
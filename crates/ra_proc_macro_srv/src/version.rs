@@ -0,0 +1,111 @@
+//! Detects which rustc compiled a given proc-macro dylib.
+//!
+//! A dylib rustc produces normally carries a `.rustc` metadata section whose
+//! compressed payload records the compiler version that emitted it (see
+//! `parse_rustc_section` for the layout). We read that instead of
+//! assuming our own bridge ABI, so `dylib::ProcMacroLibraryLibloading::open`
+//! can select the matching `Abi` (or refuse a dylib it doesn't understand)
+//! before it ever transmutes the registrar symbol. The caller hands us an
+//! already-parsed `object::File` so the dylib is only mapped and parsed once
+//! per load, shared with the registrar symbol lookup in `dylib.rs`.
+
+use std::convert::TryInto;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+
+use object::{Object, ObjectSection};
+
+fn invalid_data_err(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, e)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RustCInfo {
+    pub(crate) version: (u32, u32, u32),
+    pub(crate) channel: String,
+    pub(crate) commit: Option<String>,
+}
+
+/// Reads the embedded compiler version out of `object`'s `.rustc` section.
+///
+/// Returns `Ok(None)` (rather than an error) when the section is missing, as
+/// is the case for older or stripped dylibs; callers should fall back to the
+/// legacy, version-less load path in that case.
+pub(crate) fn read_dylib_info(object: &object::File) -> Result<Option<RustCInfo>, IoError> {
+    let section = match object.section_by_name(".rustc") {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let data = section.data().map_err(invalid_data_err)?;
+
+    parse_rustc_section(data).map(Some)
+}
+
+/// Layout (written by rustc's `rmeta::encoder`, see `METADATA_HEADER` and
+/// `rustc_metadata::rmeta::decoder::metadata_decode_version`):
+/// `b"rust"` magic (bytes 0..4), a big-endian `u32` metadata version (bytes
+/// 4..8), a big-endian `u32` root-position word (bytes 8..12), then from
+/// byte 12 onward the snappy-compressed crate metadata, which contains a
+/// `"rustc x.y.z (channel ...)"` string.
+fn parse_rustc_section(section: &[u8]) -> Result<RustCInfo, IoError> {
+    const MAGIC: &[u8] = b"rust";
+    const HEADER_LEN: usize = 12;
+    // Versions this reader has been checked against; rmeta isn't a stable
+    // format, so an unknown version is rejected rather than misread.
+    const SUPPORTED_METADATA_VERSIONS: std::ops::RangeInclusive<u32> = 5..=9;
+
+    if section.len() < HEADER_LEN {
+        return Err(invalid_data_err("'.rustc' section is too small"));
+    }
+    if &section[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data_err("'.rustc' section has no 'rust' magic"));
+    }
+
+    let metadata_version = u32::from_be_bytes(section[4..8].try_into().unwrap());
+    if !SUPPORTED_METADATA_VERSIONS.contains(&metadata_version) {
+        return Err(invalid_data_err(format!(
+            "unsupported rmeta metadata version {}",
+            metadata_version
+        )));
+    }
+
+    let mut decompressed = Vec::new();
+    snap::read::FrameDecoder::new(&section[HEADER_LEN..])
+        .read_to_end(&mut decompressed)
+        .map_err(invalid_data_err)?;
+
+    let text = String::from_utf8_lossy(&decompressed);
+    let start = text.find("rustc ").ok_or_else(|| {
+        invalid_data_err("could not find a 'rustc x.y.z' string in '.rustc' metadata")
+    })?;
+    let rest = &text[start + "rustc ".len()..];
+    let end = rest.find(|c: char| c.is_whitespace() && c != ' ').unwrap_or(rest.len());
+    parse_rustc_version_str(rest[..end].trim())
+}
+
+fn parse_rustc_version_str(s: &str) -> Result<RustCInfo, IoError> {
+    // e.g. "1.55.0 (c8dfcfe04 2021-09-06)" or "1.58.0-nightly (abcdef123 2021-11-01)"
+    let mut parts = s.splitn(2, ' ');
+    let version_and_channel = parts.next().unwrap_or(s);
+    let rest = parts.next();
+
+    let (version_str, channel) = match version_and_channel.split_once('-') {
+        Some((version, channel)) => (version, channel.to_string()),
+        None => (version_and_channel, "stable".to_string()),
+    };
+
+    let mut version_parts = version_str.split('.');
+    let mut next = || -> Result<u32, IoError> {
+        version_parts
+            .next()
+            .ok_or_else(|| invalid_data_err(format!("malformed rustc version {:?}", s)))?
+            .parse()
+            .map_err(invalid_data_err)
+    };
+    let version = (next()?, next()?, next()?);
+
+    let commit = rest
+        .and_then(|rest| rest.trim_matches(|c| c == '(' || c == ')').split(' ').next())
+        .map(|s| s.to_string());
+
+    Ok(RustCInfo { version, channel, commit })
+}